@@ -1,15 +1,76 @@
+use std::collections::HashMap;
+
 use lexopt::ValueExt;
 
+mod parse;
+
+struct Field {
+    name: Option<String>,
+    width: u32,
+}
+
+enum Unpack {
+    Offsets(Vec<u32>),
+    Fields(Vec<Field>),
+}
+
+fn parse_unpack(input: &str) -> Result<Unpack, String> {
+    if input.contains(':') {
+        let mut fields = Vec::new();
+        for entry in input.split(',') {
+            let (name, width) = entry.split_once(':').ok_or_else(|| format!("Invalid field '{}', expected name:width", entry))?;
+            let width: u32 = width.parse().map_err(|_| format!("Invalid field width in '{}'", entry))?;
+            if width == 0 {
+                return Err(format!("Field '{}' has a zero width", entry));
+            }
+            let name = if name.is_empty() { None } else { Some(name.to_owned()) };
+            fields.push(Field { name, width });
+        }
+        Ok(Unpack::Fields(fields))
+    } else {
+        let mut offsets = Vec::new();
+        for num_str in input.split(',') {
+            let num: u32 = num_str.parse().map_err(|_| format!("Invalid offset '{}'", num_str))?;
+            offsets.push(num);
+        }
+        offsets.sort();
+        Ok(Unpack::Offsets(offsets))
+    }
+}
+
+fn field_mask(width: u32) -> u64 {
+    if width == 0 {
+        0
+    } else if width >= 64 {
+        u64::MAX
+    } else {
+        u64::MAX >> (64 - width)
+    }
+}
+
+fn parse_fixed(input: &str) -> Result<(u32, u32), String> {
+    let (integer, fraction) = input.split_once('.').ok_or_else(|| format!("Invalid --fixed '{}', expected M.N", input))?;
+    let integer: u32 = integer.parse().map_err(|_| format!("Invalid integer bit count in '{}'", input))?;
+    let fraction: u32 = fraction.parse().map_err(|_| format!("Invalid fraction bit count in '{}'", input))?;
+    Ok((integer, fraction))
+}
+
 struct Args {
     input: String,
     width: Option<u32>,
-    unpack: Vec<u32>,
+    unpack: Option<Unpack>,
+    defines: HashMap<String, u64>,
+    fixed: Option<(u32, u32)>,
+    unsigned_fixed: bool,
 }
 
 fn parse_args() -> Result<Args, lexopt::Error> {
     let mut input = None;
     let mut width = None;
-    let mut unpack = Vec::new();
+    let mut unpack = None;
+    let mut defines = HashMap::new();
+    let mut fixed = None;
+    let mut unsigned_fixed = false;
 
     let mut parser = lexopt::Parser::from_env();
     while let Some(arg) = parser.next()? {
@@ -36,11 +97,24 @@ fn parse_args() -> Result<Args, lexopt::Error> {
                 let val = parser.value()?;
                 let val2 = val.clone();
                 let val_str = val.string()?;
-                for num_str in val_str.split(',') {
-                    let num: u32 = num_str.parse().map_err(|_| lexopt::Error::UnexpectedArgument(val2.clone()))?;
-                    unpack.push(num);
-                }
-                unpack.sort();
+                unpack = Some(parse_unpack(&val_str).map_err(|_| lexopt::Error::UnexpectedArgument(val2.clone()))?);
+            }
+            lexopt::Arg::Long("define") => {
+                let val = parser.value()?;
+                let val2 = val.clone();
+                let val_str = val.string()?;
+                let (name, expr) = val_str.split_once('=').ok_or_else(|| lexopt::Error::UnexpectedArgument(val2.clone()))?;
+                let value = parse::parse(expr, &defines).map_err(|_| lexopt::Error::UnexpectedArgument(val2.clone()))?;
+                defines.insert(name.to_owned(), value);
+            }
+            lexopt::Arg::Long("fixed") => {
+                let val = parser.value()?;
+                let val2 = val.clone();
+                let val_str = val.string()?;
+                fixed = Some(parse_fixed(&val_str).map_err(|_| lexopt::Error::UnexpectedArgument(val2.clone()))?);
+            }
+            lexopt::Arg::Long("unsigned-fixed") => {
+                unsigned_fixed = true;
             }
             lexopt::Arg::Long("help") => {
                 usage();
@@ -53,53 +127,39 @@ fn parse_args() -> Result<Args, lexopt::Error> {
         input: input.ok_or("missing input argument")?,
         width,
         unpack,
+        defines,
+        fixed,
+        unsigned_fixed,
     })
 }
 
 fn usage() {
-    println!("Usage: bitparse [-w|--width=b|w|d|q] [-u|--unpack=offset[,offset]] <value>");
-    println!("<value> can be in decimal, or prefixed with 0x (hex), 0o (octal), or 0b (binary).");
+    println!("Usage: bitparse [-w|--width=b|w|d|q] [-u|--unpack=...] <value>");
+    println!("<value> is a bitwise expression, e.g. \"(0xF0 | 0x0F) & ~0b1000 << 2\".");
+    println!("Numbers can be in decimal, or prefixed with 0x (hex), 0o (octal), or 0b (binary).");
     println!("Options:");
     println!("  -w, --width=[b|w|d|q]\t\t Force set bit width");
     println!("  -u, --unpack=offset[,offset]\t Unpack fields at specified bit offsets");
+    println!("  -u, --unpack=name:width[,name:width]\t Unpack named fields packed from bit 0 upward, use an empty name for a reserved gap");
+    println!("  --define=NAME=EXPR\t\t Define a named constant, usable as an identifier in <value>");
+    println!("  --fixed=M.N\t\t\t Interpret the value as a Qm.n fixed-point number");
+    println!("  --unsigned-fixed\t\t Treat --fixed's bits as unsigned instead of two's-complement");
+    println!();
+    println!("Built-in functions: popcount, clz, ctz, bswap16, bswap32, bswap64, rotl(x,n), rotr(x,n), hi, lo");
     std::process::exit(0);
 }
 
-
-fn adjust_width(width: usize) -> u32 {
-    match width {
-        1..=8 => 8,
-        9..=16 => 16,
-        17..=32 => 32,
-        33..=64 => 64,
-        _ => 64,
-    }
-}
-
 fn main() {
-    let mut args = parse_args().unwrap_or_else(|e| {
+    let args = parse_args().unwrap_or_else(|e| {
         eprintln!("Error parsing arguments: {}\n", e);
         usage();
         std::process::exit(1);
     });
 
-    let value = if args.input.starts_with("0x") {
-        let input = args.input.trim_start_matches("0x");
-        if args.width.is_none() {
-            args.width = Some(adjust_width(input.len().div_ceil(2) * 8));
-        }
-        u64::from_str_radix(input, 16).expect("Failed to parse hex input")
-    } else if args.input.starts_with("0o") {
-        u64::from_str_radix(args.input.trim_start_matches("0o"), 8).expect("Failed to parse octal input")
-    } else if args.input.starts_with("0b") {
-        let input = args.input.trim_start_matches("0b");
-        if args.width.is_none() {
-            args.width = Some(adjust_width(input.len().div_ceil(8) * 8));
-        }
-        u64::from_str_radix(input, 2).expect("Failed to parse binary input")
-    } else {
-        args.input.parse::<u64>().expect("Failed to parse decimal input")
-    };
+    let value = parse::parse(&args.input, &args.defines).unwrap_or_else(|e| {
+        eprintln!("Error parsing expression: {}", e);
+        std::process::exit(1);
+    });
 
     let width = args.width.unwrap_or({
         if value > u32::MAX as u64 {
@@ -149,6 +209,28 @@ fn main() {
         }
         _ => unreachable!(),
     }
+    if let Some((integer_bits, fraction_bits)) = args.fixed {
+        let bits = integer_bits + fraction_bits;
+        if bits > width {
+            eprintln!(
+                "Error: --fixed {}.{} needs {} bits, which exceeds the detected width of {} bits",
+                integer_bits, fraction_bits, bits, width
+            );
+            std::process::exit(1);
+        }
+        let mask = if bits == 64 { u64::MAX } else { (1u64 << bits) - 1 };
+        let raw = value & mask;
+        let scaled = if args.unsigned_fixed || bits == 0 {
+            raw as f64
+        } else {
+            let shift = 64 - bits;
+            ((raw << shift) as i64 >> shift) as f64
+        };
+        let fraction_scale = if fraction_bits == 64 { u64::MAX } else { 1u64 << fraction_bits };
+        let real = scaled / fraction_scale as f64;
+        let decimals = (fraction_bits as f64 * std::f64::consts::LOG10_2).ceil() as usize;
+        println!("Fixed-point Q{}.{}: {:.prec$}", integer_bits, fraction_bits, real, prec = decimals);
+    }
     println!("Bits:");
     for i in (0..width).rev() {
         let bit = if ((value >> i) & 1) != 0 { '1' } else { '0' };
@@ -162,25 +244,55 @@ fn main() {
         print!("    {:>2} - {:<2}       ", i, i - 7);
     }
     println!();
-    if !args.unpack.is_empty() {
-        println!("Unpacked fields:");
-        for i in 0..args.unpack.len() - 1 {
-            let this_offset = args.unpack[i];
-            if this_offset >= width {
-                break;
+    match &args.unpack {
+        Some(Unpack::Offsets(offsets)) if !offsets.is_empty() => {
+            println!("Unpacked fields:");
+            for i in 0..offsets.len() {
+                let this_offset = offsets[i];
+                if this_offset >= width {
+                    break;
+                }
+                let next_offset = offsets.get(i + 1).copied().unwrap_or(width);
+                let field_width = next_offset - this_offset;
+                let field_value = (value >> this_offset) & field_mask(field_width);
+                println!(
+                    "  Bits {:>2} to {:>2}: {} (0x{:02X}) (0b{:0width$b})",
+                    this_offset,
+                    next_offset - 1,
+                    field_value,
+                    field_value,
+                    field_value,
+                    width = field_width as usize
+                );
+            }
+        }
+        Some(Unpack::Fields(fields)) => {
+            let total_width: u32 = fields.iter().map(|field| field.width).sum();
+            if total_width > width {
+                eprintln!(
+                    "Error: unpack fields total {} bits, which exceeds the detected width of {} bits",
+                    total_width, width
+                );
+                std::process::exit(1);
+            }
+            println!("Unpacked fields:");
+            let mut offset = 0;
+            for field in fields {
+                let field_value = (value >> offset) & field_mask(field.width);
+                let label = field.name.as_deref().unwrap_or("(reserved)");
+                println!(
+                    "  {:<10} Bits {:>2} to {:>2}: {} (0x{:02X}) (0b{:0width$b})",
+                    label,
+                    offset,
+                    offset + field.width - 1,
+                    field_value,
+                    field_value,
+                    field_value,
+                    width = field.width as usize
+                );
+                offset += field.width;
             }
-            let next_offset = args.unpack.get(i + 1).copied().unwrap_or(width);
-            let width = next_offset - this_offset;
-            let field_value = (value >> this_offset) & ((1u64 << width) - 1);
-            println!(
-                "  Bits {:>2} to {:>2}: {} (0x{:02X}) (0b{:0width$b})",
-                this_offset,
-                next_offset - 1,
-                field_value,
-                field_value,
-                field_value,
-                width = width as usize
-            );
         }
+        _ => {}
     }
 }
@@ -1,8 +1,11 @@
+use std::collections::HashMap;
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Token {
     Unknown(char),
     OpenParen,
     CloseParen,
+    Comma,
     UnaryOperator(String),
     Operator(String),
     Number(String),
@@ -21,27 +24,68 @@ fn lex(input: &str) -> Result<Vec<Token>, String> {
             ')' => {
                 tokens.push(Token::CloseParen);
             }
+            ',' => {
+                tokens.push(Token::Comma);
+            }
             '<' => {
                 if iter.peek() == Some(&'<') {
                     iter.next();
                     tokens.push(Token::Operator("<<".to_owned()));
+                } else if iter.peek() == Some(&'=') {
+                    iter.next();
+                    tokens.push(Token::Operator("<=".to_owned()));
+                } else {
+                    tokens.push(Token::Operator("<".to_owned()));
                 }
             }
             '>' => {
                 if iter.peek() == Some(&'>') {
                     iter.next();
                     tokens.push(Token::Operator(">>".to_owned()));
+                } else if iter.peek() == Some(&'=') {
+                    iter.next();
+                    tokens.push(Token::Operator(">=".to_owned()));
+                } else {
+                    tokens.push(Token::Operator(">".to_owned()));
+                }
+            }
+            '=' => match iter.peek() {
+                Some(&'=') => {
+                    iter.next();
+                    tokens.push(Token::Operator("==".to_owned()));
+                }
+                _ => tokens.push(Token::Unknown(c)),
+            },
+            '!' => {
+                if iter.peek() == Some(&'=') {
+                    iter.next();
+                    tokens.push(Token::Operator("!=".to_owned()));
+                } else {
+                    tokens.push(Token::UnaryOperator(c.to_string()));
+                }
+            }
+            '&' => {
+                if iter.peek() == Some(&'&') {
+                    iter.next();
+                    tokens.push(Token::Operator("&&".to_owned()));
+                } else {
+                    tokens.push(Token::Operator(c.to_string()));
                 }
             }
-            '~' | '!' => {
+            '|' => {
+                if iter.peek() == Some(&'|') {
+                    iter.next();
+                    tokens.push(Token::Operator("||".to_owned()));
+                } else {
+                    tokens.push(Token::Operator(c.to_string()));
+                }
+            }
+            '~' => {
                 tokens.push(Token::UnaryOperator(c.to_string()));
             }
-            '^' | '*' | '/' | '%' | '+' | '-' | '|' | '&' => {
+            '^' | '*' | '/' | '%' | '+' | '-' => {
                 tokens.push(Token::Operator(c.to_string()));
             }
-            'x' | 'o' | 'b' => {
-                //prefix for data types, then loop
-            }
             '0'..='9' => {
                 let mut value = c.to_string();
                 if c == '0' {
@@ -60,7 +104,7 @@ fn lex(input: &str) -> Result<Vec<Token>, String> {
                 }
                 while let Some(cc) = iter.peek() {
                     match cc {
-                        '0'..='9' | 'A'..='F' | 'a'..='f' => {
+                        '0'..='9' | 'A'..='F' | 'a'..='f' | '_' => {
                             //worry about validity during parse
                             value.push(*cc);
                             iter.next();
@@ -78,7 +122,7 @@ fn lex(input: &str) -> Result<Vec<Token>, String> {
                 let mut value = c.to_string();
                 while let Some(cc) = iter.peek() {
                     match cc {
-                        'A'..='Z' | 'a'..='z' => {
+                        'A'..='Z' | 'a'..='z' | '0'..='9' | '_' => {
                             value.push(*cc);
                             iter.next();
                         }
@@ -107,13 +151,14 @@ struct Operator {
     precedence: i32,
 }
 
-pub fn parse(input: &str) -> Result<u64, String> {
+pub fn parse(input: &str, symbols: &HashMap<String, u64>) -> Result<u64, String> {
     let tokens = lex(input)?;
 
     let mut operators = Vec::new();
     let mut operands = Vec::new();
+    let mut arg_counts: Vec<usize> = Vec::new();
     let mut prev_token = None;
-    for token in &tokens {
+    for (i, token) in tokens.iter().enumerate() {
         match token {
             Token::OpenParen => queue_operator(token, 100, &mut operators, &mut operands)?,
             Token::CloseParen => {
@@ -121,6 +166,7 @@ pub fn parse(input: &str) -> Result<u64, String> {
                 while let Some(op) = operators.pop() {
                     if let Token::OpenParen = op.token {
                         openned = true;
+                        break;
                     } else {
                         apply_operator(&op, &mut operands)?;
                     }
@@ -128,6 +174,28 @@ pub fn parse(input: &str) -> Result<u64, String> {
                 if !openned {
                     return Err("Missing open bracket".into());
                 }
+                if matches!(operators.last(), Some(Operator { token: Token::Identifier(_), .. })) {
+                    let name = match operators.pop() {
+                        Some(Operator { token: Token::Identifier(name), .. }) => name,
+                        _ => unreachable!("just matched above"),
+                    };
+                    let arg_count = arg_counts.pop().ok_or_else(|| format!("Missing argument count for {}", name))? + 1;
+                    call_function(&name, arg_count, &mut operands)?;
+                }
+            }
+            Token::Comma => {
+                while let Some(top) = operators.last() {
+                    if matches!(top.token, Token::OpenParen) {
+                        break;
+                    }
+                    let op = operators.pop().expect("checked by while let");
+                    apply_operator(&op, &mut operands)?;
+                }
+                let in_function_call = operators.len() >= 2 && matches!(operators[operators.len() - 2].token, Token::Identifier(_));
+                if !in_function_call {
+                    return Err("Unexpected comma outside function call".into());
+                }
+                *arg_counts.last_mut().expect("function call has an argument count") += 1;
             }
             Token::UnaryOperator(_) => queue_operator(token, 1, &mut operators, &mut operands)?,
             Token::Operator(op) => {
@@ -141,8 +209,17 @@ pub fn parse(input: &str) -> Result<u64, String> {
                 Ok(num) => operands.push(num),
                 Err(_) => return Err(format!("Unrecognised number {}", num_str)),
             },
-            Token::Identifier(_) => {
-                //push onto function stack
+            Token::Identifier(name) => {
+                if matches!(tokens.get(i + 1), Some(Token::OpenParen)) {
+                    operators.push(Operator {
+                        token: token.clone(),
+                        precedence: 100,
+                    });
+                    arg_counts.push(0);
+                } else {
+                    let value = symbols.get(name).ok_or_else(|| format!("Unknown identifier '{}'", name))?;
+                    operands.push(*value);
+                }
             }
             Token::Unknown(c) => return Err(format!("Unrecognised token {}", c)),
         }
@@ -164,16 +241,47 @@ pub fn parse(input: &str) -> Result<u64, String> {
 }
 
 fn is_prev_compatible(prev_token: Option<&Token>) -> bool {
-    !(prev_token.is_none() || matches!(prev_token, Some(Token::OpenParen)) || matches!(prev_token, Some(Token::Operator(_))))
+    !(prev_token.is_none()
+        || matches!(prev_token, Some(Token::OpenParen))
+        || matches!(prev_token, Some(Token::Comma))
+        || matches!(prev_token, Some(Token::Operator(_))))
+}
+
+fn call_function(name: &str, arg_count: usize, operands: &mut Vec<u64>) -> Result<(), String> {
+    let pop_arg = |operands: &mut Vec<u64>| operands.pop().ok_or_else(|| format!("Missing argument for {}", name));
+
+    let result = match (name, arg_count) {
+        ("popcount", 1) => pop_arg(operands)?.count_ones() as u64,
+        ("clz", 1) => pop_arg(operands)?.leading_zeros() as u64,
+        ("ctz", 1) => pop_arg(operands)?.trailing_zeros() as u64,
+        ("bswap16", 1) => (pop_arg(operands)? as u16).swap_bytes() as u64,
+        ("bswap32", 1) => (pop_arg(operands)? as u32).swap_bytes() as u64,
+        ("bswap64", 1) => pop_arg(operands)?.swap_bytes(),
+        ("hi", 1) => pop_arg(operands)? >> 32,
+        ("lo", 1) => pop_arg(operands)? & 0xFFFF_FFFF,
+        ("rotl", 2) => {
+            let n = pop_arg(operands)?;
+            let x = pop_arg(operands)?;
+            x.rotate_left(n as u32)
+        }
+        ("rotr", 2) => {
+            let n = pop_arg(operands)?;
+            let x = pop_arg(operands)?;
+            x.rotate_right(n as u32)
+        }
+        _ => return Err(format!("Unknown function '{}' with {} argument(s)", name, arg_count)),
+    };
+    operands.push(result);
+    Ok(())
 }
 
-fn parse_number(input: &str) -> Result<u64, std::num::ParseIntError> {
+fn parse_number(input: &str) -> Result<u64, String> {
     let mut number = input;
     let radix = if input.starts_with("0x") {
         number = input.trim_start_matches("0x");
         16
     } else if input.starts_with("0o") {
-        number = input.trim_start_matches("00");
+        number = input.trim_start_matches("0o");
         8
     } else if input.starts_with("0b") {
         number = input.trim_start_matches("0b");
@@ -182,7 +290,22 @@ fn parse_number(input: &str) -> Result<u64, std::num::ParseIntError> {
         10
     };
 
-    u64::from_str_radix(number, radix)
+    if number.starts_with('_') || number.ends_with('_') || number.contains("__") {
+        return Err(format!("Misplaced digit separator in '{}'", input));
+    }
+
+    let digits: String = number.chars().filter(|c| *c != '_').collect();
+    u64::from_str_radix(&digits, radix).map_err(|e| e.to_string())
+}
+
+fn checked_shift(a: u64, b: u64, op_str: &str) -> Result<u64, String> {
+    let shift: u32 = b.try_into().map_err(|_| format!("Shift amount {} is out of range", b))?;
+    let result = match op_str {
+        "<<" => a.checked_shl(shift),
+        ">>" => a.checked_shr(shift),
+        _ => unreachable!("checked_shift only called for << and >>"),
+    };
+    result.ok_or_else(|| format!("Shift amount {} overflows a 64-bit value", b))
 }
 
 fn apply_operator(op: &Operator, operands: &mut Vec<u64>) -> Result<(), String> {
@@ -197,21 +320,34 @@ fn apply_operator(op: &Operator, operands: &mut Vec<u64>) -> Result<(), String>
         let a = operands.pop().ok_or(format!("not enought operands for {:?}", op.token))?;
 
         match op_str.as_str() {
-            "*" => operands.push(a * b),
+            "*" => operands.push(a.wrapping_mul(b)),
             "/" => {
                 if b == 0 {
                     return Err(format!("Divide by zero '{} / {}'", a, b));
                 }
                 operands.push(a / b)
             }
-            "%" => operands.push(a % b),
-            "+" => operands.push(a + b),
-            "-" => operands.push(a - b),
-            ">>" => operands.push(a >> b),
-            "<<" => operands.push(a << b),
+            "%" => {
+                if b == 0 {
+                    return Err(format!("Divide by zero '{} % {}'", a, b));
+                }
+                operands.push(a % b)
+            }
+            "+" => operands.push(a.wrapping_add(b)),
+            "-" => operands.push(a.wrapping_sub(b)),
+            ">>" => operands.push(checked_shift(a, b, op_str)?),
+            "<<" => operands.push(checked_shift(a, b, op_str)?),
             "|" => operands.push(a | b),
             "&" => operands.push(a & b),
             "^" => operands.push(a ^ b),
+            "==" => operands.push((a == b).into()),
+            "!=" => operands.push((a != b).into()),
+            "<" => operands.push((a < b).into()),
+            "<=" => operands.push((a <= b).into()),
+            ">" => operands.push((a > b).into()),
+            ">=" => operands.push((a >= b).into()),
+            "&&" => operands.push((a != 0 && b != 0).into()),
+            "||" => operands.push((a != 0 || b != 0).into()),
             _ => return Err(format!("Unsupported operator {:?}", op.token)),
         }
     } else {
@@ -230,16 +366,24 @@ fn operator_precedence(op_str: &str) -> i32 {
         "-" => 3,
         ">>" => 4,
         "<<" => 4,
-        "&" => 5,
-        "^" => 5,
-        "|" => 6,
-        _ => 7,
+        "<" => 5,
+        "<=" => 5,
+        ">" => 5,
+        ">=" => 5,
+        "==" => 6,
+        "!=" => 6,
+        "&" => 7,
+        "^" => 7,
+        "|" => 8,
+        "&&" => 9,
+        "||" => 10,
+        _ => 11,
     }
 }
 
 fn queue_operator(token: &Token, precedence: i32, operators: &mut Vec<Operator>, operands: &mut Vec<u64>) -> Result<(), String> {
-    while let Some(top_op) = operators.first() {
-        if precedence <= top_op.precedence {
+    while let Some(top_op) = operators.last() {
+        if top_op.precedence > precedence || matches!(token, Token::OpenParen) {
             break;
         }
         let stack_op = operators.pop().expect("should have item");